@@ -12,23 +12,52 @@
 
 use crate::common::dropout::XDropout;
 use crate::deberta::deberta_model::{PositionAttentionType, PositionAttentionTypes};
+use crate::deberta::quantization::QuantizedLinear;
 use crate::deberta::DebertaConfig;
 use crate::RustBertError;
 use std::borrow::Borrow;
 use tch::nn::Init;
 use tch::{nn, Device, Kind, Tensor};
 
+/// A linear projection that is either kept in full precision or has been converted to a
+/// weight-only quantized layer by [`DisentangledSelfAttention::in_situ_quantize`].
+pub enum ProjectionWeights {
+    Full(nn::Linear),
+    Quantized(QuantizedLinear),
+}
+
+impl ProjectionWeights {
+    fn forward(&self, x: &Tensor) -> Tensor {
+        match self {
+            ProjectionWeights::Full(linear) => x.apply(linear),
+            ProjectionWeights::Quantized(quantized) => quantized.forward(x),
+        }
+    }
+
+    /// Returns the dense weight matrix, dequantizing it first if necessary. Used where the
+    /// weight needs to be sliced/chunked (the per-head `query_states` projection) rather than
+    /// applied directly.
+    fn weight(&self) -> Tensor {
+        match self {
+            ProjectionWeights::Full(linear) => linear.ws.shallow_clone(),
+            ProjectionWeights::Quantized(quantized) => quantized.dequantized_weight(),
+        }
+    }
+}
+
 pub struct DisentangledSelfAttention {
-    in_proj: nn::Linear,
+    in_proj: ProjectionWeights,
     q_bias: Tensor,
     v_bias: Tensor,
     num_attention_heads: i64,
     head_logits_proj: Option<nn::Linear>,
     head_weights_proj: Option<nn::Linear>,
-    pos_proj: Option<nn::Linear>,
-    pos_q_proj: Option<nn::Linear>,
+    pos_proj: Option<ProjectionWeights>,
+    pos_q_proj: Option<ProjectionWeights>,
     pos_att_type: PositionAttentionTypes,
     max_relative_positions: Option<i64>,
+    position_buckets: Option<i64>,
+    position_biased_input: bool,
     pos_dropout: Option<XDropout>,
     dropout: XDropout,
 }
@@ -49,12 +78,12 @@ impl DisentangledSelfAttention {
             ..Default::default()
         };
 
-        let in_proj = nn::linear(
+        let in_proj = ProjectionWeights::Full(nn::linear(
             p / "in_proj",
             config.hidden_size,
             all_head_size * 3,
             linear_no_bias_config,
-        );
+        ));
         let q_bias = p.var("q_bias", &[all_head_size], Init::Const(0.0));
         let v_bias = p.var("v_bias", &[all_head_size], Init::Const(0.0));
         let pos_att_type = config
@@ -64,6 +93,7 @@ impl DisentangledSelfAttention {
 
         let relative_attention = config.relative_attention.unwrap_or(false);
         let talking_head = config.talking_head.unwrap_or(false);
+        let position_biased_input = config.position_biased_input.unwrap_or(true);
 
         let (head_logits_proj, head_weights_proj) = if talking_head {
             (
@@ -84,6 +114,8 @@ impl DisentangledSelfAttention {
             (None, None)
         };
 
+        let position_buckets = config.position_buckets.filter(|buckets| *buckets > 0);
+
         let (max_relative_positions, pos_dropout, pos_proj, pos_q_proj) = if relative_attention {
             let mut max_relative_positions = config.max_relative_positions.unwrap_or(-1);
             if max_relative_positions < 1 {
@@ -93,24 +125,24 @@ impl DisentangledSelfAttention {
             let pos_proj = if pos_att_type.has_type(PositionAttentionType::c2p)
                 | pos_att_type.has_type(PositionAttentionType::p2p)
             {
-                Some(nn::linear(
+                Some(ProjectionWeights::Full(nn::linear(
                     p / "pos_proj",
                     config.hidden_size,
                     all_head_size,
                     linear_no_bias_config,
-                ))
+                )))
             } else {
                 None
             };
             let pos_q_proj = if pos_att_type.has_type(PositionAttentionType::p2c)
                 | pos_att_type.has_type(PositionAttentionType::p2p)
             {
-                Some(nn::linear(
+                Some(ProjectionWeights::Full(nn::linear(
                     p / "pos_q_proj",
                     config.hidden_size,
                     all_head_size,
                     Default::default(),
-                ))
+                )))
             } else {
                 None
             };
@@ -135,11 +167,61 @@ impl DisentangledSelfAttention {
             pos_q_proj,
             pos_att_type,
             max_relative_positions,
+            position_buckets,
+            position_biased_input,
             pos_dropout,
             dropout,
         }
     }
 
+    /// Whether absolute position embeddings are added at the input embedding layer, as opposed
+    /// to only being re-injected at the Enhanced Mask Decoder layers.
+    pub fn position_biased_input(&self) -> bool {
+        self.position_biased_input
+    }
+
+    /// Converts an already-loaded full precision module into a weight-only quantized one, so a
+    /// pre-quantized checkpoint is not required. `in_proj`, `pos_proj` and `pos_q_proj` are
+    /// calibrated to `bits`-bit integers with per-group scales; `q_bias` and `v_bias` are kept
+    /// in full precision.
+    pub fn in_situ_quantize(&self, bits: i64, group_size: i64) -> DisentangledSelfAttention {
+        let quantize = |projection: &ProjectionWeights| {
+            let bias = match projection {
+                ProjectionWeights::Full(linear) => linear.bs.as_ref().map(Tensor::shallow_clone),
+                ProjectionWeights::Quantized(_) => None,
+            };
+            ProjectionWeights::Quantized(QuantizedLinear::from_calibration(
+                &projection.weight(),
+                bias,
+                bits,
+                group_size,
+            ))
+        };
+
+        DisentangledSelfAttention {
+            in_proj: quantize(&self.in_proj),
+            q_bias: self.q_bias.shallow_clone(),
+            v_bias: self.v_bias.shallow_clone(),
+            num_attention_heads: self.num_attention_heads,
+            head_logits_proj: self.head_logits_proj.as_ref().map(|p| nn::Linear {
+                ws: p.ws.shallow_clone(),
+                bs: p.bs.as_ref().map(Tensor::shallow_clone),
+            }),
+            head_weights_proj: self.head_weights_proj.as_ref().map(|p| nn::Linear {
+                ws: p.ws.shallow_clone(),
+                bs: p.bs.as_ref().map(Tensor::shallow_clone),
+            }),
+            pos_proj: self.pos_proj.as_ref().map(quantize),
+            pos_q_proj: self.pos_q_proj.as_ref().map(quantize),
+            pos_att_type: self.pos_att_type.clone(),
+            max_relative_positions: self.max_relative_positions,
+            position_buckets: self.position_buckets,
+            position_biased_input: self.position_biased_input,
+            pos_dropout: self.pos_dropout.clone(),
+            dropout: self.dropout.clone(),
+        }
+    }
+
     fn transpose_for_scores(&self, x: &Tensor) -> Tensor {
         let mut new_shape = x.size();
         let _ = new_shape.pop();
@@ -155,10 +237,49 @@ impl DisentangledSelfAttention {
         }
     }
 
-    fn build_relative_position(&self, query_size: i64, key_size: i64, device: Device) -> Tensor {
+    /// Log-bucketizes a raw linear relative position as used by DeBERTa-v2/v3, so relative
+    /// offsets within `bucket_size / 2` of the query are left untouched, and offsets beyond that
+    /// are compressed onto a logarithmic scale that reaches `bucket_size - 1` at an offset of
+    /// `max_position - 1`. Larger offsets are not clamped here and map past `bucket_size - 1`.
+    fn make_log_bucket_position(
+        relative_pos: &Tensor,
+        bucket_size: i64,
+        max_position: i64,
+    ) -> Tensor {
+        let sign = relative_pos.sign();
+        let mid = bucket_size / 2;
+        let abs_pos_raw = relative_pos.abs();
+        let within_mid = abs_pos_raw.le(mid);
+        let abs_pos = Tensor::full(
+            &relative_pos.size(),
+            (mid - 1) as f64,
+            (Kind::Float, relative_pos.device()),
+        )
+        .where_self(&within_mid, &abs_pos_raw.to_kind(Kind::Float));
+        let log_pos = ((&abs_pos / mid as f64).log()
+            / ((max_position - 1) as f64 / mid as f64).ln()
+            * (mid - 1) as f64)
+            .ceil()
+            + mid as f64;
+        let log_pos = log_pos * sign.to_kind(Kind::Float);
+        relative_pos.where_self(&within_mid, &log_pos.to_kind(Kind::Int64))
+    }
+
+    fn build_relative_position(
+        query_size: i64,
+        key_size: i64,
+        device: Device,
+        bucket_size: i64,
+        max_position: i64,
+    ) -> Tensor {
         let q_ids = Tensor::arange(query_size, (Kind::Int64, device));
         let k_ids = Tensor::arange(key_size, (Kind::Int64, device));
         let rel_pos_ids = q_ids.unsqueeze(-1) - k_ids.view([1, -1]).repeat(&[query_size, 1]);
+        let rel_pos_ids = if bucket_size > 0 && max_position > 0 {
+            Self::make_log_bucket_position(&rel_pos_ids, bucket_size, max_position)
+        } else {
+            rel_pos_ids
+        };
         rel_pos_ids.slice(0, 0, query_size, 1).unsqueeze(0)
     }
 
@@ -221,7 +342,7 @@ impl DisentangledSelfAttention {
         &self,
         query_layer: &Tensor,
         key_layer: &Tensor,
-        relative_pos: Option<&Tensor>,
+        relative_pos: &Tensor,
         relative_embeddings: &Tensor,
         scale_factor: f64,
     ) -> Result<Tensor, RustBertError> {
@@ -229,16 +350,7 @@ impl DisentangledSelfAttention {
         key_layer_size.reverse();
         let mut query_layer_size = query_layer.size();
         query_layer_size.reverse();
-        let calc_relative_pos = if relative_pos.is_none() {
-            Some(self.build_relative_position(
-                query_layer_size[1],
-                key_layer_size[1],
-                query_layer.device(),
-            ))
-        } else {
-            None
-        };
-        let relative_pos = relative_pos.unwrap_or_else(|| calc_relative_pos.as_ref().unwrap());
+        let bucket_size = self.position_buckets.unwrap_or(-1);
         let relative_pos = match &relative_pos.dim() {
             2 => relative_pos.unsqueeze(0).unsqueeze(0),
             3 => relative_pos.unsqueeze(1),
@@ -251,33 +363,31 @@ impl DisentangledSelfAttention {
             }
         };
 
-        let attention_span = *[
-            *[query_layer.size()[1], key_layer.size()[1]]
-                .iter()
-                .max()
-                .unwrap(),
-            self.max_relative_positions.unwrap(),
-        ]
-        .iter()
-        .min()
-        .unwrap();
-
-        let relative_embeddings = relative_embeddings
-            .slice(
-                0,
-                self.max_relative_positions.unwrap() - attention_span,
-                self.max_relative_positions.unwrap() + attention_span,
-                1,
-            )
-            .unsqueeze(0);
+        // `relative_embeddings` has already been sliced to the attention span and unsqueezed by
+        // the caller's `RelativePositionCache`; only the clamp range for the gathers below still
+        // needs `attention_span`, which is cheap to recompute per layer.
+        let attention_span = if bucket_size > 0 {
+            bucket_size
+        } else {
+            *[
+                *[query_layer_size[1], key_layer_size[1]]
+                    .iter()
+                    .max()
+                    .unwrap(),
+                self.max_relative_positions.unwrap(),
+            ]
+            .iter()
+            .min()
+            .unwrap()
+        };
 
         let pos_key_layer = if let Some(pos_proj) = &self.pos_proj {
-            Some(self.transpose_for_scores(&relative_embeddings.apply(pos_proj)))
+            Some(self.transpose_for_scores(&pos_proj.forward(relative_embeddings)))
         } else {
             None
         };
         let pos_query_layer = if let Some(pos_q_proj) = &self.pos_q_proj {
-            Some(self.transpose_for_scores(&relative_embeddings.apply(pos_q_proj)))
+            Some(self.transpose_for_scores(&pos_q_proj.forward(relative_embeddings)))
         } else {
             None
         };
@@ -302,10 +412,12 @@ impl DisentangledSelfAttention {
             let pos_query_layer = &pos_query_layer
                 / (*pos_query_layer.size().last().unwrap() as f64 * scale_factor).sqrt();
             let r_pos = if query_layer_size[1] != key_layer_size[1] {
-                self.build_relative_position(
+                Self::build_relative_position(
                     key_layer_size[1],
                     key_layer_size[1],
                     query_layer.device(),
+                    bucket_size,
+                    self.max_relative_positions.unwrap(),
                 )
             } else {
                 relative_pos.copy()
@@ -338,12 +450,12 @@ impl DisentangledSelfAttention {
         hidden_states: &Tensor,
         attention_mask: Option<&Tensor>,
         query_states: Option<&Tensor>,
-        relative_pos: Option<&Tensor>,
-        relative_embeddings: Option<&Tensor>,
+        relative_position_cache: Option<&RelativePositionCache>,
         train: bool,
     ) -> Result<Tensor, RustBertError> {
         let (query_layer, key_layer, value_layer) = if let Some(query_states) = query_states {
-            let ws = self.in_proj.ws.chunk(self.num_attention_heads * 3, 0);
+            let in_proj_weight = self.in_proj.weight();
+            let ws = in_proj_weight.chunk(self.num_attention_heads * 3, 0);
             let query_key_value_weights = (0..3)
                 .map(|k| {
                     Tensor::cat(
@@ -374,7 +486,7 @@ impl DisentangledSelfAttention {
             ));
             (query_layer, key_layer, value_layer)
         } else {
-            let qp = hidden_states.apply(&self.in_proj);
+            let qp = self.in_proj.forward(hidden_states);
             let mut layers = self.transpose_for_scores(&qp).chunk(3, -1);
             (
                 layers.pop().unwrap(),
@@ -393,19 +505,251 @@ impl DisentangledSelfAttention {
         let query_layer = query_layer / scale;
         let mut attention_scores = query_layer.matmul(&key_layer.transpose(-1, -2));
 
-        if let Some(relative_embeddings) = relative_embeddings {
-            let relative_embeddings =
-                relative_embeddings.apply_t(self.pos_dropout.as_ref().unwrap(), train);
+        if let Some(cache) = relative_position_cache {
+            let relative_embeddings = cache
+                .relative_embeddings()
+                .apply_t(self.pos_dropout.as_ref().unwrap(), train);
             let relative_attention = self.disentangled_att_bias(
                 &query_layer,
                 &key_layer,
-                relative_pos,
+                cache.relative_pos(),
                 &relative_embeddings,
                 scale_factor,
             )?;
             attention_scores = attention_scores + relative_attention;
         }
 
-        Ok(Tensor::new())
+        let query_layer_size = query_layer.size();
+        let mut attention_scores = attention_scores.view([
+            -1,
+            self.num_attention_heads,
+            query_layer_size[2],
+            key_layer.size()[2],
+        ]);
+
+        if let Some(head_logits_proj) = &self.head_logits_proj {
+            attention_scores = attention_scores
+                .permute(&[0, 2, 3, 1])
+                .apply(head_logits_proj)
+                .permute(&[0, 3, 1, 2]);
+        }
+
+        let fully_masked_rows = if let Some(attention_mask) = attention_mask {
+            // `attention_mask` is the broadcastable `[batch, 1, query, key]` extended mask
+            // built by the encoder, with zeros marking positions to block.
+            attention_scores =
+                attention_scores.masked_fill(&attention_mask.eq(0), f64::NEG_INFINITY);
+            // A query row with no unmasked key softmaxes to all-NaN (every logit is -inf);
+            // zero those rows out afterwards, matching the reference `XSoftmax`.
+            Some(attention_mask.eq(0).all_dim(-1, true))
+        } else {
+            None
+        };
+
+        let attention_probs = attention_scores.softmax(-1, attention_scores.kind());
+        let attention_probs = if let Some(fully_masked_rows) = fully_masked_rows {
+            attention_probs.masked_fill(&fully_masked_rows, 0.0)
+        } else {
+            attention_probs
+        };
+        let mut attention_probs = attention_probs.apply_t(&self.dropout, train);
+
+        if let Some(head_weights_proj) = &self.head_weights_proj {
+            attention_probs = attention_probs
+                .permute(&[0, 2, 3, 1])
+                .apply(head_weights_proj)
+                .permute(&[0, 3, 1, 2]);
+        }
+
+        let context_layer = attention_probs.matmul(&value_layer);
+        let context_layer = context_layer.permute(&[0, 2, 1, 3]).contiguous();
+        let mut new_context_layer_shape = context_layer.size();
+        let _ = new_context_layer_shape.pop();
+        let _ = new_context_layer_shape.pop();
+        new_context_layer_shape.push(-1);
+        let context_layer = context_layer.view(new_context_layer_shape.as_slice());
+
+        Ok(context_layer)
+    }
+}
+
+/// A single Enhanced Mask Decoder layer: disentangled self-attention followed by the usual
+/// BERT-style self-output sublayer (dense projection, dropout, residual, `LayerNorm`). Mirrors
+/// `DebertaAttention`/`SelfOutput` from the reference implementation so that stacking
+/// `EnhancedMaskDecoder` layers composes real transformer blocks instead of dropping the
+/// residual stream after each attention call.
+struct EmdLayer {
+    self_attention: DisentangledSelfAttention,
+    dense: nn::Linear,
+    layer_norm: nn::LayerNorm,
+    dropout: XDropout,
+}
+
+impl EmdLayer {
+    fn new<'p, P>(p: P, config: &DebertaConfig) -> EmdLayer
+    where
+        P: Borrow<nn::Path<'p>>,
+    {
+        let p = p.borrow();
+        let self_attention = DisentangledSelfAttention::new(p / "attention" / "self", config);
+        let dense = nn::linear(
+            p / "attention" / "output" / "dense",
+            config.hidden_size,
+            config.hidden_size,
+            Default::default(),
+        );
+        let layer_norm_config = nn::LayerNormConfig {
+            eps: config.layer_norm_eps.unwrap_or(1e-7),
+            ..Default::default()
+        };
+        let layer_norm = nn::layer_norm(
+            p / "attention" / "output" / "LayerNorm",
+            vec![config.hidden_size],
+            layer_norm_config,
+        );
+        let dropout = XDropout::new(config.hidden_dropout_prob);
+
+        EmdLayer {
+            self_attention,
+            dense,
+            layer_norm,
+            dropout,
+        }
+    }
+
+    /// Runs attention with `query_states` as the query and, per `DebertaAttention`, as the
+    /// residual the self-output sublayer adds back before the final `LayerNorm`.
+    fn forward_t(
+        &self,
+        hidden_states: &Tensor,
+        query_states: &Tensor,
+        attention_mask: Option<&Tensor>,
+        relative_position_cache: Option<&RelativePositionCache>,
+        train: bool,
+    ) -> Result<Tensor, RustBertError> {
+        let self_output = self.self_attention.forward_t(
+            hidden_states,
+            attention_mask,
+            Some(query_states),
+            relative_position_cache,
+            train,
+        )?;
+        let projected = self_output.apply(&self.dense).apply_t(&self.dropout, train);
+        Ok((projected + query_states).apply(&self.layer_norm))
+    }
+}
+
+/// DeBERTa's Enhanced Mask Decoder. Absolute position embeddings are added back in only at
+/// these last `num_emd_layers` layers (as `query_states`), rather than at the embedding input,
+/// so the encoder proper stays purely relative-position-aware and only the decoding layers used
+/// for the masked-LM head see absolute positions.
+pub struct EnhancedMaskDecoder {
+    layers: Vec<EmdLayer>,
+}
+
+impl EnhancedMaskDecoder {
+    pub fn new<'p, P>(p: P, config: &DebertaConfig) -> EnhancedMaskDecoder
+    where
+        P: Borrow<nn::Path<'p>>,
+    {
+        let p = p.borrow();
+        let num_emd_layers = config.num_emd_layers.unwrap_or(1).max(0);
+        let layers = (0..num_emd_layers)
+            .map(|layer_index| EmdLayer::new(p / layer_index, config))
+            .collect();
+        EnhancedMaskDecoder { layers }
+    }
+
+    /// Runs the (fixed) encoder output through the EMD layers. `hidden_states +
+    /// absolute_position_embeddings` seeds `query_states` once, before the first layer; from
+    /// then on only `query_states` evolves (each layer's post-`LayerNorm` output becomes the
+    /// next layer's query), while keys/values keep coming from the original, unchanged
+    /// `hidden_states` throughout, matching the reference EMD. `relative_position_cache` is
+    /// shared across layers so the relative positions and relative embedding slice are computed
+    /// once.
+    pub fn forward_t(
+        &self,
+        hidden_states: &Tensor,
+        absolute_position_embeddings: &Tensor,
+        attention_mask: Option<&Tensor>,
+        relative_position_cache: Option<&RelativePositionCache>,
+        train: bool,
+    ) -> Result<Tensor, RustBertError> {
+        let mut query_states = hidden_states + absolute_position_embeddings;
+        for layer in &self.layers {
+            query_states = layer.forward_t(
+                hidden_states,
+                &query_states,
+                attention_mask,
+                relative_position_cache,
+                train,
+            )?;
+        }
+        Ok(query_states)
+    }
+}
+
+/// Precomputes the pieces of DeBERTa's disentangled attention bias that are identical across
+/// every stacked attention layer in a single forward pass — the relative position ids (the
+/// `arange`/subtraction in [`DisentangledSelfAttention::build_relative_position`]) and the
+/// attention-span slice of the shared relative position embeddings. The encoder builds one of
+/// these per forward pass and passes it by reference into each layer's `forward_t`, instead of
+/// every layer recomputing identical tensors.
+pub struct RelativePositionCache {
+    relative_pos: Tensor,
+    relative_embeddings: Tensor,
+}
+
+impl RelativePositionCache {
+    pub fn new(
+        query_len: i64,
+        key_len: i64,
+        device: Device,
+        max_relative_positions: i64,
+        position_buckets: Option<i64>,
+        relative_embeddings: &Tensor,
+    ) -> RelativePositionCache {
+        let bucket_size = position_buckets.unwrap_or(-1);
+        let relative_pos = DisentangledSelfAttention::build_relative_position(
+            query_len,
+            key_len,
+            device,
+            bucket_size,
+            max_relative_positions,
+        );
+
+        let (attention_span, embedding_center) = if bucket_size > 0 {
+            (bucket_size, bucket_size)
+        } else {
+            let attention_span = *[
+                *[query_len, key_len].iter().max().unwrap(),
+                max_relative_positions,
+            ]
+            .iter()
+            .min()
+            .unwrap();
+            (attention_span, max_relative_positions)
+        };
+        let relative_embeddings = relative_embeddings
+            .slice(
+                0,
+                embedding_center - attention_span,
+                embedding_center + attention_span,
+                1,
+            )
+            .unsqueeze(0);
+
+        RelativePositionCache {
+            relative_pos,
+            relative_embeddings,
+        }
+    }
+
+    pub fn relative_pos(&self) -> &Tensor {
+        &self.relative_pos
+    }
+
+    pub fn relative_embeddings(&self) -> &Tensor {
+        &self.relative_embeddings
     }
 }
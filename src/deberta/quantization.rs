@@ -0,0 +1,168 @@
+// Copyright 2020, Microsoft and the HuggingFace Inc. team.
+// Copyright 2020 Guillaume Becquin
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//     http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use tch::{Kind, Tensor};
+
+/// Packs `8 / bits` quantized codes into each byte of `codes` (shape `[out_features,
+/// in_features]`, values in `[0, 2^bits - 1]`), padding `in_features` up to a multiple of
+/// `8 / bits` if needed. `bits` must be 4 or 8 so codes are byte-aligned.
+fn pack_codes(codes: &Tensor, bits: i64) -> Tensor {
+    let codes_per_byte = 8 / bits;
+    let out_features = codes.size()[0];
+    let in_features = codes.size()[1];
+    let num_bytes = (in_features + codes_per_byte - 1) / codes_per_byte;
+    let padded_len = num_bytes * codes_per_byte;
+
+    let codes = if padded_len > in_features {
+        let pad = Tensor::zeros(
+            &[out_features, padded_len - in_features],
+            (codes.kind(), codes.device()),
+        );
+        Tensor::cat(&[codes, &pad], 1)
+    } else {
+        codes.shallow_clone()
+    };
+
+    let grouped = codes.view([out_features, num_bytes, codes_per_byte]);
+    let mut packed = Tensor::zeros(&[out_features, num_bytes], (Kind::Int64, codes.device()));
+    for i in 0..codes_per_byte {
+        packed = packed + grouped.select(2, i) * (1i64 << (bits * i));
+    }
+    packed.to_kind(Kind::Uint8)
+}
+
+/// Inverse of [`pack_codes`]: unpacks `packed` back into one code per input feature, dropping
+/// the padding columns beyond `in_features`.
+fn unpack_codes(packed: &Tensor, bits: i64, in_features: i64) -> Tensor {
+    let codes_per_byte = 8 / bits;
+    let out_features = packed.size()[0];
+    let packed = packed.to_kind(Kind::Int64);
+
+    let mut columns = Vec::with_capacity(codes_per_byte as usize);
+    for i in 0..codes_per_byte {
+        let shifted = (&packed / (1i64 << (bits * i))).to_kind(Kind::Int64);
+        columns.push(shifted.fmod(1i64 << bits).unsqueeze(-1));
+    }
+    Tensor::cat(&columns, -1)
+        .view([out_features, -1])
+        .slice(1, 0, in_features, 1)
+}
+
+/// Weight-only quantized linear layer using a GPTQ-style WNA16 layout: the weight matrix is
+/// quantized `group_size` input features per group, each group carrying its own per-output-row
+/// fp16 scale and zero-point, with `g_idx` mapping every input feature to its group. Codes are
+/// then bit-packed (`8 / bits` per byte) so the stored weight is actually `bits`-bits-per-weight,
+/// not one byte/element, which is where the memory savings over the fp16 original come from.
+/// Dequantization follows `w = (q - zero) * scale` before the forward matmul.
+pub struct QuantizedLinear {
+    qweight: Tensor,
+    in_features: i64,
+    scales: Tensor,
+    zeros: Tensor,
+    g_idx: Tensor,
+    bias: Option<Tensor>,
+    bits: i64,
+    group_size: i64,
+}
+
+impl QuantizedLinear {
+    /// Calibrates a quantized layer from an already-loaded full precision weight matrix
+    /// (shape `[out_features, in_features]`) using per-group min/max calibration, so a
+    /// pre-quantized checkpoint is not required. `bits` must be 4 or 8.
+    pub fn from_calibration(
+        weight: &Tensor,
+        bias: Option<Tensor>,
+        bits: i64,
+        group_size: i64,
+    ) -> QuantizedLinear {
+        assert!(
+            bits == 4 || bits == 8,
+            "QuantizedLinear only supports 4 or 8 bit codes, got {bits}"
+        );
+        let levels = (1i64 << bits) - 1;
+        let in_features = *weight.size().last().unwrap();
+        let num_groups = (in_features + group_size - 1) / group_size;
+
+        let mut scales = Vec::with_capacity(num_groups as usize);
+        let mut zeros = Vec::with_capacity(num_groups as usize);
+        let mut quantized_groups = Vec::with_capacity(num_groups as usize);
+        for group in 0..num_groups {
+            let start = group * group_size;
+            let end = (start + group_size).min(in_features);
+            // Per-group calibration is done per output row, so every output channel keeps its
+            // own scale/zero-point within a group (standard GPTQ WNA16 layout).
+            let group_weight = weight.slice(1, start, end, 1);
+            let (min, _) = group_weight.min_dim(1, true);
+            let (max, _) = group_weight.max_dim(1, true);
+            let scale = ((&max - &min) / levels as f64).clamp_min(1e-8);
+            // Clamp into the representable `[0, levels]` range: an all-positive (or
+            // all-negative) group otherwise yields a zero-point outside that range, which wraps
+            // around when packed into the unsigned byte and corrupts dequantization.
+            let zero = (-&min / &scale).round().clamp(0.0, levels as f64);
+            let quantized_group = (&group_weight / &scale + &zero)
+                .round()
+                .clamp(0.0, levels as f64);
+
+            quantized_groups.push(quantized_group);
+            scales.push(scale);
+            zeros.push(zero);
+        }
+
+        let codes = Tensor::cat(&quantized_groups, 1).to_kind(Kind::Int64);
+        let qweight = pack_codes(&codes, bits);
+        let scales = Tensor::cat(&scales, 1).to_kind(Kind::Half);
+        let zeros = Tensor::cat(&zeros, 1).to_kind(Kind::Uint8);
+        let g_idx = (Tensor::arange(in_features, (Kind::Int64, weight.device())) / group_size)
+            .to_kind(Kind::Int64);
+
+        QuantizedLinear {
+            qweight,
+            in_features,
+            scales,
+            zeros,
+            g_idx,
+            bias,
+            bits,
+            group_size,
+        }
+    }
+
+    /// Unpacks the codes and reconstructs the dense weight matrix, broadcasting each group's
+    /// scale/zero-point to the input features it owns via `g_idx`.
+    pub fn dequantized_weight(&self) -> Tensor {
+        let codes = unpack_codes(&self.qweight, self.bits, self.in_features);
+        let scales = self
+            .scales
+            .index_select(1, &self.g_idx)
+            .to_kind(Kind::Float);
+        let zeros = self.zeros.index_select(1, &self.g_idx).to_kind(Kind::Float);
+        (codes.to_kind(Kind::Float) - zeros) * scales
+    }
+
+    /// Dequantizes the packed weight and runs the linear projection. Backends with a fused
+    /// dequant-matmul kernel can swap this body without changing call sites.
+    pub fn forward(&self, x: &Tensor) -> Tensor {
+        let y = x.matmul(&self.dequantized_weight().tr());
+        match &self.bias {
+            Some(bias) => y + bias,
+            None => y,
+        }
+    }
+
+    pub fn bits(&self) -> i64 {
+        self.bits
+    }
+
+    pub fn group_size(&self) -> i64 {
+        self.group_size
+    }
+}